@@ -1,10 +1,15 @@
 use crate::output::ElasticsearchOutput;
+use brotli::Decompressor as BrotliDecoder;
+use flate2::read::GzDecoder;
 use glob::glob;
 use log::{info, warn};
 use rayon::prelude::*;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Read};
 use std::path::Path;
+use zstd::Decoder as ZstdDecoder;
+
+const INPUT_GLOBS: [&str; 4] = ["**/*.json", "**/*.json.gz", "**/*.json.zst", "**/*.json.br"];
 
 pub struct Loader<'a> {
     input_dir: &'a str,
@@ -19,39 +24,65 @@ impl<'a> Loader<'a> {
         }
     }
 
-    pub fn load(&self) -> Result<(), String> {
-        &self.initialize_es();
+    pub fn load(&self) -> Result<(usize, usize), String> {
+        let versioned_index = self.initialize_es();
         // TODO should we care other files?
-        let path = Path::new(&self.input_dir).join(Path::new("**/*.json"));
-        // read files from input_dir
-        let files: Vec<_> = glob(path.to_str().unwrap())
-            .unwrap()
+        // read files from input_dir, plain and gzip/zstd/brotli-compressed NDJSON alike
+        let files: Vec<_> = INPUT_GLOBS
+            .iter()
+            .flat_map(|pattern| {
+                let path = Path::new(&self.input_dir).join(Path::new(pattern));
+                glob(path.to_str().unwrap()).unwrap()
+            })
             .filter_map(|x| x.ok())
             .collect();
-        files
+        let (succeeded, failed) = files
             .par_iter()
-            .map(|filepath| self.load_file(filepath.to_str().unwrap()))
+            .map(|filepath| self.load_file(filepath.to_str().unwrap(), versioned_index.clone()))
             .filter_map(|x| x.ok())
-            .collect::<()>();
-        Ok(())
+            .reduce(|| (0, 0), |a, b| (a.0 + b.0, a.1 + b.1));
+        if let Some(versioned_index) = &versioned_index {
+            self.finalize_alias(versioned_index);
+        }
+        Ok((succeeded, failed))
+    }
+
+    fn initialize_es(&self) -> Option<String> {
+        let initializer = ElasticsearchOutput::new(&self.config_file, None, None);
+        initializer.initialize()
     }
 
-    fn initialize_es(&self) {
-        let initializer = ElasticsearchOutput::new(&self.config_file);
-        initializer.initialize();
+    fn finalize_alias(&self, versioned_index: &str) {
+        let finalizer = ElasticsearchOutput::new(&self.config_file, None, None);
+        finalizer.finalize_alias(versioned_index);
     }
 
-    fn load_file(&self, filepath: &str) -> Result<(), String> {
-        let mut search_engine = ElasticsearchOutput::new(&self.config_file);
+    fn load_file(&self, filepath: &str, target_index: Option<String>) -> Result<(usize, usize), String> {
+        let mut search_engine =
+            ElasticsearchOutput::new(&self.config_file, target_index, Some(filepath.to_string()));
         info!("Reading {}", filepath);
-        for line_result in BufReader::new(File::open(filepath).unwrap()).lines() {
+        let reader = BufReader::new(Loader::open_reader(filepath));
+        for line_result in reader.lines() {
             match line_result {
                 Ok(line) => search_engine.add_document(line),
                 Err(error) => warn!("Can not read line. {:?}", error),
             }
         }
-        search_engine.close();
+        let counts = search_engine.close();
         info!("Finish: {}", filepath);
-        Ok(())
+        Ok(counts)
+    }
+
+    fn open_reader(filepath: &str) -> Box<dyn Read> {
+        let file = File::open(filepath).unwrap();
+        if filepath.ends_with(".gz") {
+            Box::new(GzDecoder::new(file))
+        } else if filepath.ends_with(".zst") {
+            Box::new(ZstdDecoder::new(file).expect("Fail initializing zstd decoder"))
+        } else if filepath.ends_with(".br") {
+            Box::new(BrotliDecoder::new(file, 4096))
+        } else {
+            Box::new(file)
+        }
     }
 }
@@ -40,8 +40,8 @@ fn main() {
     let input_dir = matches.value_of("INPUT_DIR").unwrap();
 
     match load(input_dir, config_file) {
-        Ok(()) => {
-            info!("{}", "done");
+        Ok((succeeded, failed)) => {
+            info!("done. succeeded: {}, failed: {}", succeeded, failed);
         }
         Err(msg) => error!("{}", msg),
     }
@@ -1,13 +1,21 @@
+use chrono::Utc;
 use elasticsearch::auth::Credentials;
+use elasticsearch::cert::{Certificate, CertificateValidation};
 use elasticsearch::http::request::JsonBody;
 use elasticsearch::http::transport::{SingleNodeConnectionPool, Transport, TransportBuilder};
 use elasticsearch::http::StatusCode;
-use elasticsearch::indices::{IndicesCreateParts, IndicesExistsParts};
+use elasticsearch::indices::{
+    IndicesCreateParts, IndicesDeleteParts, IndicesExistsParts, IndicesGetAliasParts,
+    IndicesUpdateAliasesParts,
+};
 use elasticsearch::{BulkParts, Elasticsearch};
+use futures::stream::{self, StreamExt};
 use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Map, Value};
 use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use url::Url;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -17,9 +25,53 @@ pub struct EsConfig {
     index_name: String,
     schema_file: String,
     id_field_name: String,
+    action_field_name: Option<String>,
     cloud_id: Option<String>,
     user: Option<String>,
     password: Option<String>,
+    #[serde(default)]
+    alias_mode: bool,
+    alias_name: Option<String>,
+    #[serde(default = "default_max_retries")]
+    max_retries: u32,
+    #[serde(default = "default_retry_base_ms")]
+    retry_base_ms: u64,
+    #[serde(default = "default_retry_max_ms")]
+    retry_max_ms: u64,
+    tls: Option<TlsConfig>,
+    #[serde(default = "default_concurrency")]
+    concurrency: usize,
+    dead_letter_dir: Option<String>,
+}
+
+fn default_concurrency() -> usize {
+    4
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TlsConfig {
+    validation: TlsValidation,
+    ca_cert: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum TlsValidation {
+    None,
+    Full,
+    CaCert,
+}
+
+fn default_max_retries() -> u32 {
+    5
+}
+
+fn default_retry_base_ms() -> u64 {
+    500
+}
+
+fn default_retry_max_ms() -> u64 {
+    30_000
 }
 
 impl EsConfig {
@@ -29,12 +81,28 @@ impl EsConfig {
         let config: EsConfig = serde_yaml::from_reader(f).expect(format!("Parse Error").as_str());
         return config;
     }
+
+    fn alias_name(&self) -> &str {
+        match &self.alias_name {
+            Some(alias_name) => alias_name.as_str(),
+            None => self.index_name.as_str(),
+        }
+    }
 }
 
 pub struct ElasticsearchOutput {
     client: Elasticsearch,
     buffer: Vec<String>,
     config: EsConfig,
+    target_index: String,
+    runtime: tokio::runtime::Runtime,
+    source_file: Option<String>,
+}
+
+struct ChunkResult {
+    succeeded: usize,
+    failed: usize,
+    dead_letters: Vec<Value>,
 }
 
 fn load_schema(schema_file: &str) -> Value {
@@ -46,16 +114,24 @@ fn load_schema(schema_file: &str) -> Value {
 }
 
 impl ElasticsearchOutput {
-    pub fn new(_config_file: &str) -> Self {
+    pub fn new(_config_file: &str, target_index: Option<String>, source_file: Option<String>) -> Self {
         // read config
         let config = EsConfig::new(_config_file);
         debug!("url: {}", config.url);
         debug!("buffer_size: {}", config.buffer_size);
         let client = ElasticsearchOutput::create_elasticsearch_client(&config);
+        let target_index = target_index.unwrap_or_else(|| config.index_name.clone());
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("Fail initializing runtime");
         ElasticsearchOutput {
             client,
             config,
             buffer: vec![],
+            target_index,
+            runtime,
+            source_file,
         }
     }
 
@@ -63,37 +139,128 @@ impl ElasticsearchOutput {
         self.buffer.push(_document);
     }
 
-    pub fn initialize(&self) {
-        if self.exist_index() {
-            info!(
-                "{} index already exists. skip initialization phase.",
-                &self.config.index_name
-            );
+    pub fn initialize(&self) -> Option<String> {
+        if self.config.alias_mode {
+            let versioned_index = ElasticsearchOutput::versioned_index_name(&self.config.index_name);
+            info!("{} index is creating...", &versioned_index);
+            let task = self.call_create_versioned_index(&versioned_index);
+            self.runtime.block_on(task).expect("Something wrong...");
+            Some(versioned_index)
         } else {
-            info!("{} index is creating...", &self.config.index_name);
-            let mut _rt = tokio::runtime::Runtime::new().expect("Fail initializing runtime");
-            let task = self.call_indices_create();
-            _rt.block_on(task).expect("Something wrong...")
+            if self.exist_index() {
+                info!(
+                    "{} index already exists. skip initialization phase.",
+                    &self.config.index_name
+                );
+            } else {
+                info!("{} index is creating...", &self.config.index_name);
+                let task = self.call_indices_create();
+                self.runtime.block_on(task).expect("Something wrong...")
+            }
+            None
+        }
+    }
+
+    pub fn finalize_alias(&self, versioned_index: &str) {
+        if !self.config.alias_mode {
+            return;
+        }
+        let alias_name = self.config.alias_name();
+        let old_indices: Vec<String> = self
+            .runtime
+            .block_on(self.call_get_alias_indices())
+            .expect("Something wrong...")
+            .into_iter()
+            // a concrete index literally named like the alias is not actually attached to it
+            .filter(|index| index != alias_name)
+            .collect();
+        self.runtime
+            .block_on(self.call_swap_alias(versioned_index, &old_indices))
+            .expect("Something wrong...");
+        if !old_indices.is_empty() {
+            self.runtime
+                .block_on(self.call_delete_old_indices(&old_indices))
+                .expect("Something wrong...");
         }
     }
 
-    pub fn close(&mut self) {
+    fn versioned_index_name(index_name: &str) -> String {
+        format!("{}-{}", index_name, Utc::now().format("%Y%m%dT%H%M%S"))
+    }
+
+    pub fn close(&mut self) -> (usize, usize) {
         let chunk_size = if self.buffer.len() <= self.config.buffer_size {
             self.buffer.len()
         } else {
             self.config.buffer_size
         };
-        let mut _rt = tokio::runtime::Runtime::new().expect("Fail initializing runtime");
-        let mut tasks = vec![];
-        for chunk in self.buffer.chunks(chunk_size) {
-            let task = self.proceed_chunk(chunk);
-            tasks.push(task);
-        }
+        let concurrency = self.config.concurrency;
+        let results = self.runtime.block_on(
+            stream::iter(self.buffer.chunks(chunk_size))
+                .map(|chunk| self.proceed_chunk(chunk))
+                .buffer_unordered(concurrency)
+                .collect::<Vec<_>>(),
+        );
 
-        for task in tasks {
-            _rt.block_on(task).expect("Error on task...");
+        let mut succeeded_docs = 0usize;
+        let mut failed_docs = 0usize;
+        let mut dead_letters = Vec::new();
+        for result in results {
+            let chunk_result = result.expect("Error on task...");
+            succeeded_docs += chunk_result.succeeded;
+            failed_docs += chunk_result.failed;
+            dead_letters.extend(chunk_result.dead_letters);
+        }
+        if failed_docs > 0 {
+            warn!("{} documents failed to index after retries.", failed_docs);
+        }
+        if !dead_letters.is_empty() {
+            self.write_dead_letters(&dead_letters);
         }
         self.buffer.clear();
+        (succeeded_docs, failed_docs)
+    }
+
+    fn dead_letter_path(&self) -> Option<PathBuf> {
+        let dir = self.config.dead_letter_dir.as_ref()?;
+        let source_file = self.source_file.as_ref()?;
+        // Keep the full relative path (not just the basename) so files with the same
+        // name in different subdirectories don't collide onto one dead letter file.
+        let sanitized = Path::new(source_file)
+            .components()
+            .filter_map(|component| match component {
+                std::path::Component::Normal(part) => Some(part.to_string_lossy().into_owned()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("__");
+        Some(Path::new(dir).join(format!("{}.dlq.json", sanitized)))
+    }
+
+    fn write_dead_letters(&self, dead_letters: &[Value]) {
+        let path = match self.dead_letter_path() {
+            Some(path) => path,
+            None => {
+                warn!(
+                    "{} documents could not be processed but no dead_letter_dir is configured. Dropping them.",
+                    dead_letters.len()
+                );
+                return;
+            }
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .expect(format!("Cannot create dead letter directory. {}", dir.display()).as_str());
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .expect(format!("Cannot open dead letter file. {}", path.display()).as_str());
+        for entry in dead_letters {
+            writeln!(file, "{}", entry).expect("Cannot write to dead letter file.");
+        }
+        info!("{} documents written to {}.", dead_letters.len(), path.display());
     }
 
     fn create_credentials(config: &EsConfig) -> Option<Credentials> {
@@ -106,13 +273,38 @@ impl ElasticsearchOutput {
         }
     }
 
+    fn create_certificate_validation(config: &EsConfig) -> CertificateValidation {
+        match &config.tls {
+            None => CertificateValidation::Default,
+            Some(tls) => match tls.validation {
+                TlsValidation::None => CertificateValidation::None,
+                TlsValidation::Full => CertificateValidation::Default,
+                TlsValidation::CaCert => {
+                    let ca_cert_path = tls
+                        .ca_cert
+                        .as_ref()
+                        .expect("ca_cert path is required when tls validation is ca_cert");
+                    let bytes = std::fs::read(ca_cert_path)
+                        .expect(format!("ca_cert file is not found. {}", ca_cert_path).as_str());
+                    let cert = if ca_cert_path.ends_with(".der") {
+                        Certificate::from_der(&bytes).expect("Cannot parse ca_cert as DER.")
+                    } else {
+                        Certificate::from_pem(&bytes).expect("Cannot parse ca_cert as PEM.")
+                    };
+                    CertificateValidation::Full(cert)
+                }
+            },
+        }
+    }
+
     fn create_elasticsearch_client(config: &EsConfig) -> Elasticsearch {
         return match &config.cloud_id {
             None => {
                 debug!("Using url...");
                 let url = Url::parse(config.url.as_str()).unwrap();
-                let builder =
-                    TransportBuilder::new(SingleNodeConnectionPool::new(url)).disable_proxy();
+                let builder = TransportBuilder::new(SingleNodeConnectionPool::new(url))
+                    .disable_proxy()
+                    .cert_validation(ElasticsearchOutput::create_certificate_validation(&config));
                 match ElasticsearchOutput::create_credentials(&config) {
                     None => Elasticsearch::new(builder.build().unwrap()),
                     Some(credentials) => {
@@ -131,9 +323,8 @@ impl ElasticsearchOutput {
     }
 
     fn exist_index(&self) -> bool {
-        let mut _rt = tokio::runtime::Runtime::new().expect("Fail initializing runtime");
         let task = self.call_indices_exists();
-        _rt.block_on(task).expect("Something wrong...")
+        self.runtime.block_on(task).expect("Something wrong...")
     }
 
     async fn call_indices_create(&self) -> Result<(), String> {
@@ -169,6 +360,130 @@ impl ElasticsearchOutput {
         };
     }
 
+    async fn call_create_versioned_index(&self, versioned_index: &str) -> Result<(), String> {
+        let schema_json = load_schema(&self.config.schema_file);
+        let response = self
+            .client
+            .indices()
+            .create(IndicesCreateParts::Index(versioned_index))
+            .body(schema_json)
+            .send()
+            .await;
+        return match response {
+            Ok(response) => match &response.error_for_status_code_ref() {
+                Ok(_) => {
+                    info!("{} index was created.", versioned_index);
+                    Ok(())
+                }
+                Err(error) => {
+                    warn!(
+                        "Create index request has failed. Status Code is {:?}.",
+                        error.status_code().unwrap()
+                    );
+                    if let Ok(body) = &response.text().await {
+                        warn!("{}", body);
+                    }
+                    Err(String::from("Create index failed."))
+                }
+            },
+            Err(error) => {
+                error!("create index failed. {}", error);
+                Err(error.to_string())
+            }
+        };
+    }
+
+    async fn call_get_alias_indices(&self) -> Result<Vec<String>, String> {
+        let alias_name = self.config.alias_name();
+        let response = self
+            .client
+            .indices()
+            .get_alias(IndicesGetAliasParts::Name(&[alias_name]))
+            .send()
+            .await;
+        return match response {
+            Ok(response) => match response.status_code() {
+                StatusCode::NOT_FOUND => Ok(vec![]),
+                _ => match response.error_for_status_code() {
+                    Ok(response) => {
+                        let body: Value = response.json().await.map_err(|e| e.to_string())?;
+                        let indices = body
+                            .as_object()
+                            .map(|obj| obj.keys().cloned().collect())
+                            .unwrap_or_else(Vec::new);
+                        Ok(indices)
+                    }
+                    Err(error) => {
+                        error!("get alias failed. {}", error);
+                        Err(error.to_string())
+                    }
+                },
+            },
+            Err(error) => {
+                error!("get alias failed. {}", error);
+                Err(error.to_string())
+            }
+        };
+    }
+
+    async fn call_swap_alias(&self, versioned_index: &str, old_indices: &[String]) -> Result<(), String> {
+        let alias_name = self.config.alias_name();
+        let mut actions: Vec<Value> = old_indices
+            .iter()
+            .map(|old_index| json!({"remove": {"index": old_index, "alias": alias_name}}))
+            .collect();
+        actions.push(json!({"add": {"index": versioned_index, "alias": alias_name}}));
+        let response = self
+            .client
+            .indices()
+            .update_aliases(IndicesUpdateAliasesParts::None)
+            .body(json!({ "actions": actions }))
+            .send()
+            .await;
+        return match response {
+            Ok(response) => match response.error_for_status_code_ref() {
+                Ok(_) => {
+                    info!("alias {} now points to {}.", alias_name, versioned_index);
+                    Ok(())
+                }
+                Err(error) => {
+                    warn!("Swap alias request has failed. {}", error);
+                    Err(String::from("Swap alias failed."))
+                }
+            },
+            Err(error) => {
+                error!("swap alias failed. {}", error);
+                Err(error.to_string())
+            }
+        };
+    }
+
+    async fn call_delete_old_indices(&self, old_indices: &[String]) -> Result<(), String> {
+        let indices: Vec<&str> = old_indices.iter().map(String::as_str).collect();
+        let response = self
+            .client
+            .indices()
+            .delete(IndicesDeleteParts::Index(&indices))
+            .send()
+            .await;
+        return match response {
+            Ok(response) => match response.error_for_status_code_ref() {
+                Ok(_) => {
+                    info!("deleted old indices: {:?}", old_indices);
+                    Ok(())
+                }
+                Err(error) => {
+                    warn!("Delete old indices request has failed. {}", error);
+                    Err(String::from("Delete old indices failed."))
+                }
+            },
+            Err(error) => {
+                error!("delete old indices failed. {}", error);
+                Err(error.to_string())
+            }
+        };
+    }
+
     async fn call_indices_exists(&self) -> Result<bool, String> {
         let indices: [&str; 1] = [&self.config.index_name.as_str()];
         let result = self
@@ -201,59 +516,199 @@ impl ElasticsearchOutput {
         };
     }
 
-    async fn proceed_chunk(&self, chunk: &[String]) -> Result<(), Box<dyn std::error::Error>> {
-        let mut body: Vec<JsonBody<_>> = Vec::new();
+    async fn proceed_chunk(&self, chunk: &[String]) -> Result<ChunkResult, Box<dyn std::error::Error>> {
+        let mut pending: Vec<(Value, Option<Value>, String)> = Vec::with_capacity(chunk.len());
+        let mut dead_letters = Vec::new();
+        let mut failed_docs = 0usize;
         for d in chunk {
-            let doc_map: Map<String, Value> =
-                serde_json::from_str(d.as_str()).expect("something wrong during parsing json");
-            let id = match doc_map.get(self.config.id_field_name.as_str()) {
-                None => panic!("ID not found... skip this line. {}", d),
-                Some(id_value) => match id_value.as_str() {
-                    None => panic!("ID not found... skip this line. {}"),
-                    Some(id_str) => id_str,
-                },
+            let doc_map: Map<String, Value> = match serde_json::from_str(d.as_str()) {
+                Ok(doc_map) => doc_map,
+                Err(error) => {
+                    warn!("Cannot parse line as json. {} line:[{}]", error, d);
+                    dead_letters.push(ElasticsearchOutput::dead_letter_entry(error.to_string(), d));
+                    failed_docs += 1;
+                    continue;
+                }
+            };
+            let id = match doc_map
+                .get(self.config.id_field_name.as_str())
+                .and_then(Value::as_str)
+            {
+                None => {
+                    warn!("ID not found... skip this line. {}", d);
+                    dead_letters.push(ElasticsearchOutput::dead_letter_entry(
+                        format!("ID field '{}' not found", self.config.id_field_name),
+                        d,
+                    ));
+                    failed_docs += 1;
+                    continue;
+                }
+                Some(id_str) => id_str.to_string(),
             };
-            body.push(json!({"index": {"_id": id}}).into());
-            // TODO can we use d instead of doc_map?
-            body.push(JsonBody::from(serde_json::to_value(doc_map).unwrap()));
+            let (action, body) = ElasticsearchOutput::build_bulk_action(
+                &self.config.action_field_name,
+                id.as_str(),
+                doc_map,
+            );
+            pending.push((action, body, d.clone()));
         }
-        info!("Sending {} documents... ", chunk.len());
-        let bulk_response = self
-            .client
-            .bulk(BulkParts::Index(self.config.index_name.as_str()))
-            .body(body)
-            .send()
-            .await?;
-        if !bulk_response.status_code().is_success() {
-            warn!(
-                "Bulk request has failed. Status Code is {:?}. ",
-                bulk_response.status_code(),
+
+        let mut succeeded_docs = 0usize;
+        let mut attempt = 0u32;
+        while !pending.is_empty() {
+            info!(
+                "Sending {} documents... (attempt {})",
+                pending.len(),
+                attempt + 1
             );
-            panic!("bulk indexing failed")
-        } else {
+            let body: Vec<JsonBody<_>> = pending
+                .iter()
+                .flat_map(|(action, doc, _)| match doc {
+                    Some(doc) => vec![JsonBody::from(action.clone()), JsonBody::from(doc.clone())],
+                    None => vec![JsonBody::from(action.clone())],
+                })
+                .collect();
+            let bulk_response = self
+                .client
+                .bulk(BulkParts::Index(self.target_index.as_str()))
+                .body(body)
+                .send()
+                .await?;
+
+            if !bulk_response.status_code().is_success() {
+                let status = bulk_response.status_code().as_u16();
+                if attempt >= self.config.max_retries || !ElasticsearchOutput::is_retryable_status(status) {
+                    error!(
+                        "Bulk request has failed permanently. Status Code is {}. ",
+                        status
+                    );
+                    for (_, _, source) in &pending {
+                        dead_letters.push(ElasticsearchOutput::dead_letter_entry(
+                            format!("bulk request failed with status {}", status),
+                            source,
+                        ));
+                    }
+                    failed_docs += pending.len();
+                    pending.clear();
+                    break;
+                }
+                warn!("Bulk request has failed. Status Code is {}. Retrying...", status);
+                ElasticsearchOutput::backoff_sleep(attempt, self.config.retry_base_ms, self.config.retry_max_ms).await;
+                attempt += 1;
+                continue;
+            }
+
             debug!("response : {}", bulk_response.status_code());
             let response_body = bulk_response.json::<Value>().await?;
             let successful = response_body["errors"].as_bool().unwrap() == false;
-            if successful == false {
-                warn!("Bulk Request has some errors. {:?}", successful);
-                let items = response_body["items"].as_array().unwrap();
-                for item in items {
-                    if let Some(index_obj) = item["index"].as_object() {
-                        if index_obj.contains_key("error") {
-                            if let Some(obj) = index_obj["error"].as_object() {
-                                warn!(
-                                    "error id:[{}], type:[{}], reason:[{}]",
-                                    index_obj.get("_id").unwrap(),
-                                    obj.get("type").unwrap(),
-                                    obj.get("reason").unwrap()
-                                );
-                            }
+            if successful {
+                succeeded_docs += pending.len();
+                pending.clear();
+                break;
+            }
+            warn!("Bulk Request has some errors. {:?}", successful);
+            let items = response_body["items"].as_array().unwrap();
+            let mut retryable = Vec::new();
+            for (item, doc) in items.iter().zip(pending.into_iter()) {
+                let result_obj = item
+                    .as_object()
+                    .and_then(|item| item.values().next())
+                    .and_then(Value::as_object);
+                match result_obj.and_then(|result_obj| result_obj.get("error").and_then(Value::as_object).map(|e| (result_obj, e))) {
+                    Some((result_obj, error_obj)) => {
+                        warn!(
+                            "error id:[{}], type:[{}], reason:[{}]",
+                            result_obj.get("_id").unwrap(),
+                            error_obj.get("type").unwrap(),
+                            error_obj.get("reason").unwrap()
+                        );
+                        let status = result_obj.get("status").and_then(Value::as_u64).unwrap_or(0) as u16;
+                        if ElasticsearchOutput::is_retryable_status(status) {
+                            retryable.push(doc);
+                        } else {
+                            dead_letters.push(ElasticsearchOutput::dead_letter_entry(
+                                format!("{}", error_obj.get("reason").unwrap()),
+                                &doc.2,
+                            ));
+                            failed_docs += 1;
                         }
                     }
+                    None => succeeded_docs += 1,
                 }
             }
+            if retryable.is_empty() {
+                break;
+            }
+            if attempt >= self.config.max_retries {
+                warn!(
+                    "{} documents still failing after {} attempts.",
+                    retryable.len(),
+                    attempt + 1
+                );
+                for (_, _, source) in &retryable {
+                    dead_letters.push(ElasticsearchOutput::dead_letter_entry(
+                        "still failing after max_retries retries".to_string(),
+                        source,
+                    ));
+                }
+                failed_docs += retryable.len();
+                break;
+            }
+            ElasticsearchOutput::backoff_sleep(attempt, self.config.retry_base_ms, self.config.retry_max_ms).await;
+            attempt += 1;
+            pending = retryable;
         }
         debug!("Finished bulk request.");
-        Ok(())
+        Ok(ChunkResult {
+            succeeded: succeeded_docs,
+            failed: failed_docs,
+            dead_letters,
+        })
+    }
+
+    fn dead_letter_entry(reason: String, source: &str) -> Value {
+        json!({ "reason": reason, "source": source })
+    }
+
+    fn build_bulk_action(
+        action_field_name: &Option<String>,
+        id: &str,
+        mut doc_map: Map<String, Value>,
+    ) -> (Value, Option<Value>) {
+        let action = action_field_name
+            .as_ref()
+            .and_then(|field| doc_map.get(field.as_str()))
+            .and_then(Value::as_str)
+            .unwrap_or("index")
+            .to_string();
+        if let Some(field) = action_field_name {
+            doc_map.remove(field.as_str());
+        }
+        match action.as_str() {
+            "create" => (
+                json!({"create": {"_id": id}}),
+                Some(serde_json::to_value(doc_map).unwrap()),
+            ),
+            "update" => (
+                json!({"update": {"_id": id}}),
+                Some(json!({"doc": doc_map})),
+            ),
+            "delete" => (json!({"delete": {"_id": id}}), None),
+            _ => (
+                json!({"index": {"_id": id}}),
+                Some(serde_json::to_value(doc_map).unwrap()),
+            ),
+        }
+    }
+
+    fn is_retryable_status(status: u16) -> bool {
+        status == 429 || status == 502 || status == 503 || status >= 500
+    }
+
+    async fn backoff_sleep(attempt: u32, base_ms: u64, max_ms: u64) {
+        let exp = base_ms.saturating_mul(1u64.checked_shl(attempt).unwrap_or(u64::MAX));
+        let capped = exp.min(max_ms);
+        let jitter = (rand::random::<f64>() * capped as f64) as u64;
+        tokio::time::sleep(std::time::Duration::from_millis(capped + jitter)).await;
     }
 }